@@ -13,15 +13,25 @@
 //! 3. 多图模式（图片数 >= threshold）使用独立的像素限制配置
 
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
-use image::{DynamicImage, ImageFormat, ImageReader};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use lru::LruCache;
 use std::io::Cursor;
+use std::num::NonZeroUsize;
+use rayon::prelude::*;
+use std::sync::{Mutex, OnceLock};
+use xxhash_rust::xxh3::xxh3_64;
 
 use crate::model::config::CompressionConfig;
 
 /// 图片处理结果
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct ImageProcessResult {
     /// 处理后的 base64 数据
+    ///
+    /// 体积较大，序列化（如日志 / 指标上报）时默认跳过，仅保留尺寸与 token 统计。
+    #[serde(skip_serializing)]
     pub data: String,
     /// 原始尺寸 (width, height)
     pub original_size: (u32, u32),
@@ -33,15 +43,257 @@ pub struct ImageProcessResult {
     pub was_resized: bool,
 }
 
+/// 根据配置构建解码限制
+///
+/// `max_image_width`/`max_image_height` 约束单边像素，`max_alloc` 约束解码时的
+/// 字节分配上限，超过任一上限时 `image` crate 会返回 `Err` 而非继续分配内存。
+fn decode_limits(config: &CompressionConfig) -> Limits {
+    let mut limits = Limits::no_limits();
+    limits.max_image_width = Some(config.image_decode_max_pixels);
+    limits.max_image_height = Some(config.image_decode_max_pixels);
+    limits.max_alloc = Some(config.image_decode_max_bytes);
+    limits
+}
+
+/// 将配置中的滤波器名称映射到 `image` crate 的重采样滤波器
+///
+/// 无法识别的名称回退到 `Lanczos3`（质量最高，也是历史默认值）。
+fn resolve_filter(config: &CompressionConfig) -> FilterType {
+    match config.image_filter.as_str() {
+        "nearest" => FilterType::Nearest,
+        "triangle" => FilterType::Triangle,
+        "catmullrom" => FilterType::CatmullRom,
+        "gaussian" => FilterType::Gaussian,
+        _ => FilterType::Lanczos3,
+    }
+}
+
+/// 带解码限制地全量解码图片
+///
+/// 在真正解码前，按未压缩 RGBA 估算内存占用，超预算时提前返回 `Err`，
+/// 避免恶意/超大图片把进程拖垮。
+fn decode_with_limits(
+    bytes: &[u8],
+    original_size: (u32, u32),
+    config: &CompressionConfig,
+) -> Result<DynamicImage, String> {
+    let decoded_bytes = original_size.0 as u64 * original_size.1 as u64 * 4;
+    if decoded_bytes > config.image_decode_max_bytes {
+        return Err(format!(
+            "图片解码需约 {} 字节，超过上限 {} 字节",
+            decoded_bytes, config.image_decode_max_bytes
+        ));
+    }
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("图片格式识别失败: {}", e))?
+        .limits(decode_limits(config))
+        .decode()
+        .map_err(|e| format!("图片加载失败: {}", e))
+}
+
+/// 已处理图片的缓存键：解码像素内容的哈希 + 所有影响缓存内容的配置项
+///
+/// 哈希取自解码后的像素数据，使编码方式不同但内容相同的输入共享同一条目。
+/// 由于缓存里存的是**已编码**的 `data`，凡是会改变最终像素或编码字节的配置
+/// （缩放参数、重采样滤波器、JPEG/WebP 质量）都必须并入键，否则切换这些配置
+/// 会命中旧条目返回错误编码的数据。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    pixel_hash: u64,
+    max_long_edge: u32,
+    max_pixels: u32,
+    format: String,
+    filter: String,
+    jpeg_quality: u8,
+    webp_quality: u8,
+}
+
+impl CacheKey {
+    fn new(pixel_hash: u64, max_pixels: u32, format: &str, config: &CompressionConfig) -> Self {
+        Self {
+            pixel_hash,
+            max_long_edge: config.image_max_long_edge,
+            max_pixels,
+            format: format.to_string(),
+            filter: config.image_filter.clone(),
+            jpeg_quality: config.image_jpeg_quality,
+            webp_quality: config.image_webp_quality,
+        }
+    }
+}
+
+/// 进程级有界 LRU 缓存
+///
+/// 容量在每次使用时按 `config.image_cache_capacity` 重新设置（`LruCache::resize`），
+/// 因此配置变化会立即生效，而不是被首次调用的容量永久固定。
+static IMAGE_CACHE: OnceLock<Mutex<LruCache<CacheKey, ImageProcessResult>>> = OnceLock::new();
+
+fn image_cache() -> &'static Mutex<LruCache<CacheKey, ImageProcessResult>> {
+    IMAGE_CACHE.get_or_init(|| Mutex::new(LruCache::new(NonZeroUsize::new(1).unwrap())))
+}
+
+/// 仅凭魔数 / 容器头嗅探图片尺寸，无需全量解码
+///
+/// 目前支持 SVG 与基于 ISO-BMFF 的 AVIF/HEIC；无法识别时返回 `None`，
+/// 由调用方回退到 `image` reader。
+fn sniff_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    sniff_svg(bytes).or_else(|| sniff_iso_bmff(bytes))
+}
+
+/// 从根 `<svg>` 元素的 `width`/`height` 或 `viewBox` 解析尺寸
+fn sniff_svg(bytes: &[u8]) -> Option<(u32, u32)> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let start = text.find("<svg")?;
+    let rest = &text[start..];
+    let tag = &rest[..rest.find('>')?];
+
+    if let (Some(w), Some(h)) = (svg_attr_number(tag, "width"), svg_attr_number(tag, "height")) {
+        return Some((w, h));
+    }
+
+    // 退回 viewBox 的 "min-x min-y width height"
+    let vb = svg_attr_value(tag, "viewBox")?;
+    let mut nums = vb
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| s.parse::<f64>().ok());
+    let _min_x = nums.next()?;
+    let _min_y = nums.next()?;
+    let w = nums.next()?;
+    let h = nums.next()?;
+    Some((w.round().max(1.0) as u32, h.round().max(1.0) as u32))
+}
+
+/// 取 XML 标签中某属性的原始值（处理单/双引号，要求属性名前是边界字符）
+fn svg_attr_value<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut search = tag;
+    let mut base = 0;
+    loop {
+        let idx = search.find(name)?;
+        let abs = base + idx;
+        let boundary = abs == 0 || tag.as_bytes()[abs - 1].is_ascii_whitespace();
+        let after = &tag[abs + name.len()..];
+        let trimmed = after.trim_start();
+        if boundary {
+            if let Some(rest) = trimmed.strip_prefix('=') {
+                let rest = rest.trim_start();
+                let quote = rest.chars().next()?;
+                if quote == '"' || quote == '\'' {
+                    let val = &rest[1..];
+                    return Some(&val[..val.find(quote)?]);
+                }
+            }
+        }
+        base = abs + name.len();
+        search = &tag[base..];
+    }
+}
+
+/// 取属性值并解析为像素数
+///
+/// 容忍绝对单位后缀（`"100px"`），但拒绝相对 / 百分比单位（`"100%"`、`em` 等）——
+/// 这类值无法换算成绝对像素，按“无值”处理以回退到 `viewBox`。
+fn svg_attr_number(tag: &str, name: &str) -> Option<u32> {
+    let value = svg_attr_value(tag, name)?.trim();
+    let num: String = value
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let unit = value[num.len()..].trim();
+    // CSS 绝对单位换算：1in = 96px = 72pt = 6pc = 2.54cm = 25.4mm
+    let px_per_unit = match unit {
+        "" | "px" => 1.0,
+        "pt" => 96.0 / 72.0,
+        "pc" => 16.0,
+        "in" => 96.0,
+        "cm" => 96.0 / 2.54,
+        "mm" => 96.0 / 25.4,
+        _ => return None,
+    };
+    let parsed: f64 = num.parse::<f64>().ok()? * px_per_unit;
+    if parsed < 1.0 {
+        return None;
+    }
+    Some(parsed.round() as u32)
+}
+
+/// 走 ISO-BMFF 盒子链 `meta`→`iprp`→`ipco`→`ispe` 读取 AVIF/HEIC 尺寸
+fn sniff_iso_bmff(bytes: &[u8]) -> Option<(u32, u32)> {
+    if bytes.len() < 12 || &bytes[4..8] != b"ftyp" {
+        return None;
+    }
+    // `meta` 是 FullBox，载荷前有 4 字节 version/flags
+    let meta = find_box(bytes, b"meta")?.get(4..)?;
+    let iprp = find_box(meta, b"iprp")?;
+    let ipco = find_box(iprp, b"ipco")?;
+
+    // 多项文件（缩略图 / alpha 平面 / 主图）会带多个 ispe，首个未必属于主图。
+    // 不去解析 pitm/ipma 做主项归属时，只对恰好单个 ispe 的文件走快速路径，
+    // 其余交回 image reader 兜底，避免报告错误尺寸。
+    let mut ispes = find_boxes(ipco, b"ispe");
+    let ispe = ispes.next()?;
+    if ispes.next().is_some() {
+        return None;
+    }
+
+    // ispe 载荷：4 字节 version/flags，随后是 width / height（大端 u32）
+    let w = u32::from_be_bytes(ispe.get(4..8)?.try_into().ok()?);
+    let h = u32::from_be_bytes(ispe.get(8..12)?.try_into().ok()?);
+    Some((w, h))
+}
+
+/// 在当前盒子层级查找首个指定类型的盒子，返回其载荷切片
+fn find_box<'a>(data: &'a [u8], want: &[u8; 4]) -> Option<&'a [u8]> {
+    find_boxes(data, want).next()
+}
+
+/// 遍历当前盒子层级，迭代返回所有指定类型盒子的载荷切片
+fn find_boxes<'a>(data: &'a [u8], want: &'a [u8; 4]) -> impl Iterator<Item = &'a [u8]> {
+    let mut off = 0;
+    std::iter::from_fn(move || {
+        while off + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[off..off + 4].try_into().ok()?) as usize;
+            let btype = &data[off + 4..off + 8];
+            let (header, box_end) = if size == 1 {
+                let large =
+                    u64::from_be_bytes(data.get(off + 8..off + 16)?.try_into().ok()?) as usize;
+                (16usize, off + large)
+            } else if size == 0 {
+                (8usize, data.len())
+            } else {
+                (8usize, off + size)
+            };
+            if box_end <= off || box_end > data.len() {
+                return None;
+            }
+            let matched = btype == want;
+            let payload = data.get(off + header..box_end);
+            off = box_end;
+            if matched {
+                return payload;
+            }
+        }
+        None
+    })
+}
+
 /// 从 base64 数据计算图片 token（不缩放）
 ///
 /// 返回 (tokens, width, height)，解析失败返回 None
-pub fn estimate_image_tokens(base64_data: &str) -> Option<(u64, u32, u32)> {
+pub fn estimate_image_tokens(base64_data: &str, config: &CompressionConfig) -> Option<(u64, u32, u32)> {
     let bytes = BASE64.decode(base64_data).ok()?;
-    let reader = ImageReader::new(Cursor::new(&bytes))
-        .with_guessed_format()
-        .ok()?;
-    let (width, height) = reader.into_dimensions().ok()?;
+    // 先尝试轻量魔数嗅探（SVG / AVIF / HEIC），识别失败再回退到 image reader
+    let (width, height) = match sniff_dimensions(&bytes) {
+        Some(dim) => dim,
+        None => ImageReader::new(Cursor::new(&bytes))
+            .with_guessed_format()
+            .ok()?
+            .limits(decode_limits(config))
+            // 仅读取图片头即可获得尺寸，限制不会触发全量分配
+            .into_dimensions()
+            .ok()?,
+    };
 
     // 应用 Anthropic 缩放规则计算 token
     let (scaled_w, scaled_h) = apply_scaling_rules(width, height, 1568, 1_150_000);
@@ -71,19 +323,40 @@ pub fn process_image(
     // 先只读取图片头获取尺寸（避免不必要的全量解码）
     let reader = ImageReader::new(Cursor::new(&bytes))
         .with_guessed_format()
-        .map_err(|e| format!("图片格式识别失败: {}", e))?;
+        .map_err(|e| format!("图片格式识别失败: {}", e))?
+        .limits(decode_limits(config));
     let original_size = reader
         .into_dimensions()
         .map_err(|e| format!("读取图片尺寸失败: {}", e))?;
 
     // 根据图片数量选择像素限制
-    let max_pixels = if image_count >= config.image_multi_threshold {
+    let max_pixels = select_max_pixels(config, image_count);
+
+    build_result(base64_data, &bytes, format, config, original_size, max_pixels, None)
+}
+
+/// 根据图片数量选择单图 / 多图像素预算
+fn select_max_pixels(config: &CompressionConfig, image_count: usize) -> u32 {
+    if image_count >= config.image_multi_threshold {
         config.image_max_pixels_multi
     } else {
         config.image_max_pixels_single
-    };
+    }
+}
 
-    // 计算目标尺寸
+/// 缩放 / 编码流水线的共享实现，被缓存与非缓存入口复用
+///
+/// `decoded` 可传入已解码的图片（缓存路径为算哈希已解码），避免重复解码；
+/// 传 `None` 时仅在确需缩放时才按解码限制全量解码。
+fn build_result(
+    base64_data: &str,
+    bytes: &[u8],
+    format: &str,
+    config: &CompressionConfig,
+    original_size: (u32, u32),
+    max_pixels: u32,
+    decoded: Option<DynamicImage>,
+) -> Result<ImageProcessResult, String> {
     let (target_w, target_h) = apply_scaling_rules(
         original_size.0,
         original_size.1,
@@ -95,11 +368,13 @@ pub fn process_image(
 
     // 仅在需要缩放时才全量解码图片
     let (output_data, final_size) = if needs_resize {
-        let img =
-            image::load_from_memory(&bytes).map_err(|e| format!("图片加载失败: {}", e))?;
-        let resized = img.resize(target_w, target_h, image::imageops::FilterType::Lanczos3);
+        let img = match decoded {
+            Some(img) => img,
+            None => decode_with_limits(bytes, original_size, config)?,
+        };
+        let resized = img.resize(target_w, target_h, resolve_filter(config));
         let size = (resized.width(), resized.height());
-        (encode_image(&resized, format)?, size)
+        (encode_image(&resized, format, config)?, size)
     } else {
         (base64_data.to_string(), original_size)
     };
@@ -115,6 +390,73 @@ pub fn process_image(
     })
 }
 
+/// 带缓存的图片处理
+///
+/// 以解码后像素内容的哈希与影响缓存内容的配置项为键，命中则直接返回缓存结果，
+/// 未命中则走与 [`process_image`] 相同的 [`build_result`] 流水线并写入缓存。
+/// 缓存容量由 `config.image_cache_capacity` 配置，每次调用都会按该值重设上限。
+pub fn process_image_cached(
+    base64_data: &str,
+    format: &str,
+    config: &CompressionConfig,
+    image_count: usize,
+) -> Result<ImageProcessResult, String> {
+    let bytes = BASE64
+        .decode(base64_data)
+        .map_err(|e| format!("base64 解码失败: {}", e))?;
+
+    let original_size = ImageReader::new(Cursor::new(&bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("图片格式识别失败: {}", e))?
+        .limits(decode_limits(config))
+        .into_dimensions()
+        .map_err(|e| format!("读取图片尺寸失败: {}", e))?;
+
+    let max_pixels = select_max_pixels(config, image_count);
+
+    // 哈希取自解码后的像素内容，而非 base64 字符串
+    let img = decode_with_limits(&bytes, original_size, config)?;
+    let key = CacheKey::new(xxh3_64(img.as_bytes()), max_pixels, format, config);
+
+    let cache = image_cache();
+    {
+        let mut guard = cache.lock().unwrap();
+        guard.resize(NonZeroUsize::new(config.image_cache_capacity.max(1)).unwrap());
+        if let Some(hit) = guard.get(&key) {
+            return Ok(hit.clone());
+        }
+    }
+
+    let result = build_result(
+        base64_data,
+        &bytes,
+        format,
+        config,
+        original_size,
+        max_pixels,
+        Some(img),
+    )?;
+
+    cache.lock().unwrap().put(key, result.clone());
+    Ok(result)
+}
+
+/// 批量处理图片（并行）
+///
+/// `images` 中每项为 `(base64_data, format)`。图片总数只计算一次以决定单图 / 多图
+/// 像素预算，随后用 rayon 并行解码 / 缩放 / 编码。返回值按输入顺序排列，单张图片
+/// 失败只会体现在对应的 `Err` 上，不影响其余图片。
+pub fn process_images(
+    images: &[(String, String)],
+    config: &CompressionConfig,
+) -> Vec<Result<ImageProcessResult, String>> {
+    let image_count = images.len();
+    images
+        .par_iter()
+        .map(|(data, format)| process_image(data, format, config, image_count))
+        .collect()
+}
+
 /// 应用 Anthropic 缩放规则
 ///
 /// 1. 长边不超过 max_long_edge
@@ -149,19 +491,50 @@ fn calculate_tokens(width: u32, height: u32) -> u64 {
 }
 
 /// 将图片编码为 base64
-fn encode_image(img: &DynamicImage, format: &str) -> Result<String, String> {
+///
+/// JPEG 使用配置的质量因子（`image_jpeg_quality`）编码以控制字节体积；启用 `webp`
+/// feature 时 WebP 改用 `webp` crate 按 `image_webp_quality` 有损编码；其余格式（以及
+/// 未启用该 feature 时的 WebP）沿用 `image` crate 的默认编码器。由于 token 估算只取决于最终像素尺寸，质量因子
+/// 仅影响传输字节数，不影响 token 计算。
+fn encode_image(img: &DynamicImage, format: &str, config: &CompressionConfig) -> Result<String, String> {
     let mut buffer = Cursor::new(Vec::new());
 
-    let image_format = match format {
-        "jpeg" | "jpg" => ImageFormat::Jpeg,
-        "png" => ImageFormat::Png,
-        "gif" => ImageFormat::Gif,
-        "webp" => ImageFormat::WebP,
+    match format {
+        "jpeg" | "jpg" => {
+            let encoder = JpegEncoder::new_with_quality(&mut buffer, config.image_jpeg_quality);
+            img.write_with_encoder(encoder)
+                .map_err(|e| format!("图片编码失败: {}", e))?;
+        }
+        "png" => img
+            .write_to(&mut buffer, ImageFormat::Png)
+            .map_err(|e| format!("图片编码失败: {}", e))?,
+        "gif" => img
+            .write_to(&mut buffer, ImageFormat::Gif)
+            .map_err(|e| format!("图片编码失败: {}", e))?,
+        "webp" => {
+            // 启用 `webp` feature 时改用专用编码器，按质量因子做有损压缩，
+            // 显著缩小载荷；否则回退到 image crate 的（仅无损）编码器。
+            #[cfg(feature = "webp")]
+            {
+                // `webp::Encoder` 只接受 RGB8/RGBA8，先按是否含 alpha 归一化，
+                // 避免灰度 / 16 位等变体直接报错（image crate 编码器本可处理）。
+                let encoded = if img.color().has_alpha() {
+                    let rgba = img.to_rgba8();
+                    webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                        .encode(config.image_webp_quality as f32)
+                } else {
+                    let rgb = img.to_rgb8();
+                    webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+                        .encode(config.image_webp_quality as f32)
+                };
+                return Ok(BASE64.encode(&*encoded));
+            }
+            #[cfg(not(feature = "webp"))]
+            img.write_to(&mut buffer, ImageFormat::WebP)
+                .map_err(|e| format!("图片编码失败: {}", e))?;
+        }
         _ => return Err(format!("不支持的图片格式: {}", format)),
-    };
-
-    img.write_to(&mut buffer, image_format)
-        .map_err(|e| format!("图片编码失败: {}", e))?;
+    }
 
     Ok(BASE64.encode(buffer.into_inner()))
 }
@@ -188,6 +561,57 @@ mod tests {
         assert_eq!(apply_scaling_rules(800, 600, 1568, 1_150_000), (800, 600));
     }
 
+    #[test]
+    fn test_sniff_svg() {
+        // width/height 属性（带单位）
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="640px" height="480px"></svg>"#;
+        assert_eq!(sniff_dimensions(svg), Some((640, 480)));
+
+        // 缺少 width/height 时回退到 viewBox
+        let svg = br#"<svg viewBox="0 0 320 200"></svg>"#;
+        assert_eq!(sniff_dimensions(svg), Some((320, 200)));
+
+        // 百分比等相对单位不可换算，应忽略 width/height 回退到 viewBox
+        let svg = br#"<svg width="100%" height="100%" viewBox="0 0 48 24"></svg>"#;
+        assert_eq!(sniff_dimensions(svg), Some((48, 24)));
+    }
+
+    #[test]
+    fn test_sniff_iso_bmff() {
+        // 构造最小 ftyp + meta→iprp→ipco→ispe 盒子链
+        fn boxed(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+            let size = (8 + payload.len()) as u32;
+            let mut b = size.to_be_bytes().to_vec();
+            b.extend_from_slice(kind);
+            b.extend_from_slice(payload);
+            b
+        }
+
+        fn ispe(w: u32, h: u32) -> Vec<u8> {
+            let mut p = vec![0u8; 4]; // version/flags
+            p.extend_from_slice(&w.to_be_bytes());
+            p.extend_from_slice(&h.to_be_bytes());
+            boxed(b"ispe", &p)
+        }
+
+        fn wrap(ipco_payload: &[u8]) -> Vec<u8> {
+            let iprp = boxed(b"iprp", &boxed(b"ipco", ipco_payload));
+            let mut meta_payload = vec![0u8; 4]; // FullBox version/flags
+            meta_payload.extend_from_slice(&iprp);
+            let mut buf = boxed(b"ftyp", b"avif");
+            buf.extend_from_slice(&boxed(b"meta", &meta_payload));
+            buf
+        }
+
+        // 单个 ispe：走快速路径
+        assert_eq!(sniff_dimensions(&wrap(&ispe(800, 600))), Some((800, 600)));
+
+        // 多项文件带多个 ispe：首个未必是主图，嗅探放弃并返回 None，交由调用方回退到 image reader
+        let mut two = ispe(64, 64);
+        two.extend_from_slice(&ispe(800, 600));
+        assert_eq!(sniff_dimensions(&wrap(&two)), None);
+    }
+
     #[test]
     fn test_calculate_tokens() {
         assert_eq!(calculate_tokens(1092, 1092), 1590); // 1:1 标准